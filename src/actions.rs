@@ -0,0 +1,78 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Timer};
+
+use crate::servo::{ServoBank, SERVO_MAX, SERVO_MIN};
+
+// the servo bank is shared with the TIMER1_COMPA isr (pulse multiplexing),
+// so it lives behind a plain critical section rather than an async lock:
+// isr context can never await.
+pub type SharedServoBank = Mutex<RefCell<ServoBank>>;
+
+fn set_pos(bank: &SharedServoBank, channel: usize, pos: u16) {
+    critical_section::with(|cs| bank.borrow(cs).borrow_mut().set_pos(channel, pos));
+}
+
+fn get_pos(bank: &SharedServoBank, channel: usize) -> u16 {
+    critical_section::with(|cs| bank.borrow(cs).borrow().get_pos(channel))
+}
+
+// an action to perform in response to a decoded ir command
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Press,             // drive to max and hold
+    Release,           // drive to min and hold
+    GotoAbsolute(u16), // drive to an absolute position (timer counts)
+    Nudge(i16),        // nudge the current position by a delta (timer counts)
+    Sweep,             // run the min -> max -> min sweep
+}
+
+// command -> action table, keyed on the decoded (addr, cmd) pair
+//
+// addr is u16 (rather than u8) because SamsungCommand::addr is 16 bits wide;
+// truncating it would let two different Samsung remotes collide on the same
+// table entry. NEC and RC5 addresses still fit comfortably inside it.
+//
+// update this to match whichever remote is paired with the device; the
+// addr/cmd values below are placeholders and should be replaced with the
+// values printed over serial when each button is pressed.
+pub static ACTIONS: &[(u16, u8, Action)] = &[
+    (0x00, 0x01, Action::Press),
+    (0x00, 0x02, Action::Release),
+    (0x00, 0x03, Action::Nudge(25)),
+    (0x00, 0x04, Action::Nudge(-25)),
+    (0x00, 0x05, Action::Sweep),
+];
+
+/// look up the action bound to a decoded ir command, if any
+pub fn lookup(addr: u16, cmd: u8) -> Option<Action> {
+    ACTIONS
+        .iter()
+        .find(|(a, c, _)| *a == addr && *c == cmd)
+        .map(|(_, _, action)| *action)
+}
+
+/// run an action against one channel of a servo bank shared with the isr
+///
+/// `Sweep` awaits between moves instead of blocking, so the executor can run
+/// the serial and other ir-handling tasks while a sweep is in progress.
+pub async fn dispatch(action: Action, bank: &SharedServoBank, channel: usize) {
+    match action {
+        Action::Press => set_pos(bank, channel, SERVO_MAX),
+        Action::Release => set_pos(bank, channel, SERVO_MIN),
+        Action::GotoAbsolute(pos) => set_pos(bank, channel, pos),
+        Action::Nudge(delta) => {
+            let curr = i16::try_from(get_pos(bank, channel)).unwrap_or(i16::MAX);
+            let new_pos = curr.saturating_add(delta).clamp(SERVO_MIN as i16, SERVO_MAX as i16);
+            set_pos(bank, channel, new_pos as u16);
+        }
+        Action::Sweep => {
+            set_pos(bank, channel, SERVO_MIN);
+            Timer::after(Duration::from_millis(1000)).await;
+            set_pos(bank, channel, SERVO_MAX);
+            Timer::after(Duration::from_millis(1000)).await;
+            set_pos(bank, channel, SERVO_MIN);
+        }
+    }
+}
@@ -1,48 +1,77 @@
 use arduino_hal::pac::tc0::tccr0b::CS0_A;
-use avr_device::interrupt::Mutex;
-use core::cell::Cell;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Waker;
+use critical_section::Mutex;
+use embassy_time_driver::Driver;
 
-// clock for ir timing
-pub struct Clock {
-    cntr: Mutex<Cell<u32>>, // thread-safe counter
-}
+// timing config for ir decoding / the embassy time driver; both share the
+// same 50us tick since the ir decoder's timestamps need to stay in this
+// domain and there is only the one hardware timer to generate it from
+pub const FREQ: u32 = 20_000; // 20khz = 50 microsecond ticks
+const PRESCALER: CS0_A = CS0_A::PRESCALE_8; // 16mhz atmega328p clock / 8 = 2mhz
+const TOP: u8 = 99; // 0-99 = 100 counts = 50 microseconds
 
-impl Clock {
-    // timing config for ir decoding
-    pub const FREQ: u32 = 20_000; // 20khz = 50 microseconds ticks
-    pub const PRESCALER: CS0_A = CS0_A::PRESCALE_8; // 16mhz atmega328p clock / 8 = 2mhz
-    pub const TOP: u8 = 99; // 0-99 = 100 counts = 50 microseconds
+struct AvrClockDriver {
+    ticks: AtomicU32,
+    alarm_at: AtomicU32,
+    waker: Mutex<RefCell<Option<Waker>>>,
+}
 
-    /// new clock starting at zero
-    pub const fn new() -> Clock {
-        Clock {
-            cntr: Mutex::new(Cell::new(0)),
+impl AvrClockDriver {
+    const fn new() -> Self {
+        Self {
+            ticks: AtomicU32::new(0),
+            alarm_at: AtomicU32::new(u32::MAX),
+            waker: Mutex::new(RefCell::new(None)),
         }
     }
 
-    /// configure and start hardware timer
-    #[allow(clippy::unused_self)]
-    pub fn start(&self, tc0: &arduino_hal::pac::TC0) {
-        // ctc mode (clear timer on compare)
-        tc0.tccr0a.write(|w| w.wgm0().ctc());
-        tc0.ocr0a.write(|w| w.bits(Self::TOP)); // reset every 50 microseconds
-        tc0.tccr0b.write(|w| w.cs0().variant(Self::PRESCALER)); // prescaler
-
-        // enable timer interrupt
-        tc0.timsk0.write(|w| w.ocie0a().set_bit());
+    /// advance the tick count and wake the pending alarm once it's due;
+    /// called from TIMER0_COMPA
+    fn on_tick(&self) {
+        let now = self.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+        if now >= self.alarm_at.load(Ordering::Relaxed) {
+            self.alarm_at.store(u32::MAX, Ordering::Relaxed);
+            critical_section::with(|cs| {
+                if let Some(waker) = self.waker.borrow(cs).borrow_mut().take() {
+                    waker.wake();
+                }
+            });
+        }
     }
+}
 
-    /// get current timestamp
-    pub fn now(&self) -> u32 {
-        avr_device::interrupt::free(|cs| self.cntr.borrow(cs).get())
+impl Driver for AvrClockDriver {
+    fn now(&self) -> u64 {
+        u64::from(self.ticks.load(Ordering::Relaxed))
     }
 
-    /// increment timing cntr
-    pub fn tick(&self) {
-        avr_device::interrupt::free(|cs| {
-            let c = self.cntr.borrow(cs);
-            let v = c.get();
-            c.set(v.wrapping_add(1)); // prevent overflow
-        });
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        critical_section::with(|cs| *self.waker.borrow(cs).borrow_mut() = Some(waker.clone()));
+        // the tick counter is 32 bits wide; truncate rather than panic
+        // since a wrap just means the alarm fires on the next lap
+        self.alarm_at.store(at as u32, Ordering::Relaxed);
     }
 }
+
+embassy_time_driver::time_driver_impl!(static DRIVER: AvrClockDriver = AvrClockDriver::new());
+
+/// configure and start the hardware timer backing both ir decode timing and
+/// `embassy_time::Instant::now()`
+pub fn start(tc0: &arduino_hal::pac::TC0) {
+    tc0.tccr0a.write(|w| w.wgm0().ctc()); // ctc mode (clear timer on compare)
+    tc0.ocr0a.write(|w| w.bits(TOP)); // reset every 50 microseconds
+    tc0.tccr0b.write(|w| w.cs0().variant(PRESCALER)); // prescaler
+    tc0.timsk0.write(|w| w.ocie0a().set_bit()); // enable timer interrupt
+}
+
+/// advance the clock by one tick; call from TIMER0_COMPA
+pub fn tick_isr() {
+    DRIVER.on_tick();
+}
+
+/// current tick count, in the 20khz domain the ir decoder expects
+pub fn now() -> u32 {
+    DRIVER.ticks.load(Ordering::Relaxed)
+}
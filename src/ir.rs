@@ -0,0 +1,45 @@
+use infrared::protocol::nec::NecCommand;
+use infrared::protocol::rc5::Rc5Command;
+use infrared::protocol::samsung::SamsungCommand;
+use infrared::protocol::{Nec, Rc5, SamsungNec};
+use infrared::MultiReceiver;
+
+use crate::IrPin;
+
+// protocols the receiver auto-detects between, so the device works with
+// whatever generic remote is on hand rather than requiring a NEC-only one
+pub type IrProtocols = (Nec, Rc5, SamsungNec);
+
+// multi-protocol ir receiver bound to the d2 input pin
+pub type IrReceiver = MultiReceiver<IrProtocols, IrPin, DecodedCmd>;
+
+/// a decoded ir command, tagged with the protocol that produced it, or a
+/// synthetic notice that the line has gone idle (see the gap watchdog in
+/// main.rs's TIMER0_COMPA handler)
+#[derive(Clone, Copy)]
+pub enum DecodedCmd {
+    Nec(NecCommand),
+    Rc5(Rc5Command),
+    Samsung(SamsungCommand),
+    Released,
+}
+
+// required by MultiReceiver so each protocol's decoded command can be
+// wrapped into the unified DecodedCmd it hands back from event_instant
+impl From<NecCommand> for DecodedCmd {
+    fn from(cmd: NecCommand) -> Self {
+        Self::Nec(cmd)
+    }
+}
+
+impl From<Rc5Command> for DecodedCmd {
+    fn from(cmd: Rc5Command) -> Self {
+        Self::Rc5(cmd)
+    }
+}
+
+impl From<SamsungCommand> for DecodedCmd {
+    fn from(cmd: SamsungCommand) -> Self {
+        Self::Samsung(cmd)
+    }
+}
@@ -4,12 +4,28 @@
  * CHQ1838 infrared receiver + SG90 servo control for the Arduino Uno using
  * avr-hal by Rahix: https://github.com/Rahix/avr-hal.
  *
- * This program receives infrared signals from a remote control and controls a
- * servo motor based on any button presses. Currently, any button
- * press will cause the same actions:
- *   1. move servo to maximum position
- *   2. wait 1 second
- *   3. move servo to minimum position
+ * This program receives infrared signals from a remote control and controls
+ * one or more servo motors based on the button pressed, via the action table
+ * in actions.rs. Multiple servos are driven off the single hardware timer by
+ * software-multiplexing their pulses (see servo.rs).
+ *
+ * Timer0 backs an embassy-time driver (see clock.rs), and the application
+ * itself is a small set of embassy-executor tasks instead of a single
+ * polling loop: one task reacts to decoded ir commands handed over from the
+ * PCINT2 isr via a channel, another assembles and runs serial commands the
+ * same way. Nothing blocks the executor while a servo sweep is in progress:
+ * the 1s pauses between moves are `embassy_time::Timer::after(...).await`
+ * rather than `delay_ms`.
+ *
+ * An earlier revision moved the manual global interrupt state into an RTIC
+ * app; this embassy-executor design supersedes that and is the one that
+ * ships. That RTIC variant is deliberately *not* kept alive behind a
+ * feature/bin: actions.rs and clock.rs are now async and embassy-time-driven
+ * to support the non-blocking sweep, and the RTIC tasks in the old variant
+ * were synchronous, so the two can no longer share those modules. Forking
+ * them back apart just to keep a second, unbuilt-and-untested firmware
+ * variant around would bitrot immediately and mislead the next reader more
+ * than having no `#[rtic::app]` in the tree does.
  *
  * Author: James Plummer <jamesp2001@live.co.uk>
  * Repository: https://github.com/jamesukiyo/switchbot
@@ -47,166 +63,331 @@
  */
 
 #![warn(clippy::pedantic)]
-#![allow(static_mut_refs)] // unavoidable
 #![no_std]
 #![no_main]
-#![feature(abi_avr_interrupt)] // avr interrupt handling
+#![feature(abi_avr_interrupt)]
 
 use panic_halt as _; // halt on panic
 
-use arduino_hal::delay_ms;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use arduino_hal::hal::port::PD2;
 use arduino_hal::port::mode::{Floating, Input};
 use arduino_hal::port::Pin;
 use arduino_hal::prelude::*;
 
-use avr_device::interrupt::Mutex; // interrupt-safe mutex for sharing data between main code and interrupts
+use critical_section::Mutex as CsMutex;
 
-use core::cell::Cell; // mutable memory location
-
-use infrared::protocol::nec::NecCommand;
-use infrared::protocol::Nec;
-use infrared::Receiver;
+use embassy_executor::Executor;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use static_cell::StaticCell;
 
 use ufmt::uwriteln;
 
+mod actions;
 mod clock;
+mod ir;
+mod serial_cmd;
 mod servo;
-use clock::Clock;
-use servo::{Servo, SERVO_MAX, SERVO_MIN};
-
-// types for readability
-type IrPin = Pin<Input<Floating>, PD2>; // D2 pin as floating input
-type IrProto = Nec;
-type IrCmd = NecCommand;
 
-// globals shared between main and interrupt handlers
-static CLOCK: Clock = Clock::new(); // timer for ir signal timing
-static mut RECEIVER: Option<Receiver<IrProto, IrPin>> = None;
-static SERVO: Servo = Servo::new();
+use ir::{DecodedCmd, IrReceiver};
+use serial_cmd::Command;
+use servo::{ServoBank, SERVO_MAX, SERVO_MIN};
 
-// thread-safe store for ir commands
-static CMD: Mutex<Cell<Option<IrCmd>>> = Mutex::new(Cell::new(None));
+// D2 pin as floating input, shared with ir.rs
+pub(crate) type IrPin = Pin<Input<Floating>, PD2>;
 
-// interrupt handler for D2 pin changes
-#[avr_device::interrupt(atmega328p)]
-fn PCINT2() {
-    // get ir receiver and timestamp
-    let recv = unsafe { RECEIVER.as_mut().unwrap() };
-    let now = CLOCK.now();
-
-    // try to decode ir signal
-    if let Ok(Some(cmd)) = recv.event_instant(now) {
-        // complete ir command decoded
-        avr_device::interrupt::free(|cs| {
-            let cell = CMD.borrow(cs);
-            cell.set(Some(cmd));
-        });
-        // ignored:
-        // Ok(None) = partial signal
-        // Err(_) = decode error
-        // could add LED for error indication as seen in infrared example
-    }
-}
+type Serial = arduino_hal::usart::Usart<
+    arduino_hal::pac::USART0,
+    arduino_hal::port::Pin<arduino_hal::port::mode::Input, arduino_hal::hal::port::PD0>,
+    arduino_hal::port::Pin<arduino_hal::port::mode::Output, arduino_hal::hal::port::PD1>,
+>;
 
-// timer interrupt every 50 microseconds for ir timing
-#[avr_device::interrupt(atmega328p)]
-fn TIMER0_COMPA() {
-    CLOCK.tick(); // increment timing counter
-}
-
-#[arduino_hal::entry]
-fn main() -> ! {
-    // initialise device peripherals, pins and serial
-    let dp = arduino_hal::Peripherals::take().unwrap(); // dp = device peripherals
-    let pins = arduino_hal::pins!(dp);
-    let mut serial = arduino_hal::default_serial!(dp, pins, 57600);
+// the servo wired to D9, as set up in the original single-servo firmware
+const PRIMARY_CHANNEL: usize = 0;
 
-    // start clock for time tracking
-    CLOCK.start(&dp.TC0);
+// servo bank shared between TIMER1_COMPA (pulse multiplexing) and the async
+// tasks that dispatch actions against it; see actions::SharedServoBank
+static SERVO_BANK: actions::SharedServoBank = CsMutex::new(RefCell::new(ServoBank::new()));
 
-    // configure servo pwm on d9
-    let _servo_pin = pins.d9.into_output();
+// ir receiver, filled once in main() and polled only from PCINT2
+static RECEIVER: CsMutex<RefCell<Option<IrReceiver>>> = CsMutex::new(RefCell::new(None));
 
-    // configure Timer1 for servo pwm (50hz, 20ms period)
-    dp.TC1.icr1.write(|w| w.bits(4999));
+// decoded ir commands, handed from PCINT2 to ir_task
+static IR_CMDS: Channel<CriticalSectionRawMutex, DecodedCmd, 4> = Channel::new();
 
-    // phase + frequency correct pwm
-    dp.TC1
-        .tccr1a
-        .write(|w| w.wgm1().bits(0b10).com1a().match_clear());
+// raw bytes received over uart, handed from USART_RX to serial_task
+static SERIAL_BYTES: Channel<CriticalSectionRawMutex, u8, 32> = Channel::new();
 
-    // prescaler 64 gives 250khz (16mhz atmega328p clock / 64 = 250khz)
-    dp.TC1
-        .tccr1b
-        .write(|w| w.wgm1().bits(0b11).cs1().prescale_64());
+// serial port, shared between the async tasks that log to it
+static SERIAL: AsyncMutex<CriticalSectionRawMutex, Option<Serial>> = AsyncMutex::new(None);
 
-    // initial servo position
-    // ocr1a is connected to D9 on the arduino uno
-    dp.TC1.ocr1a.write(|w| w.bits(SERVO_MIN));
+// ticks of line silence that counts as an idle gap (tick = 50us, 20khz)
+//
+// this has to comfortably outlast the repeat interval of every protocol we
+// decode, not just one frame: nec repeats roughly every ~108ms, and rc5/
+// samsung are in the same ballpark, so a ~100ms gap left only a few ms of
+// margin before a slow remote's inter-repeat silence tripped the watchdog
+// mid-hold. 3000 ticks (150ms) clears all three with headroom.
+const GAP_TICKS: u32 = 3000;
 
-    // configure pin change interrupts for ir receiver
-    dp.EXINT.pcicr.write(|w| unsafe { w.bits(0b100) });
+// tick of the last PCINT2 edge, for the idle/gap watchdog in TIMER0_COMPA
+static LAST_EDGE: AtomicU32 = AtomicU32::new(0);
 
-    // enable interrupt on PCINT18 which is pin PD2
-    dp.EXINT.pcmsk2.write(|w| w.bits(0b100));
+// set on every edge, cleared once the watchdog has synthesized a release
+// for the current gap, so it isn't re-sent on every tick while idle
+static GAP_PENDING: AtomicBool = AtomicBool::new(false);
 
-    // create ir receiver
-    let ir = Receiver::with_pin(Clock::FREQ, pins.d2);
+// interrupt handler for D2 pin changes
+#[avr_device::interrupt(atmega328p)]
+fn PCINT2() {
+    let now = clock::now();
+    LAST_EDGE.store(now, Ordering::Relaxed);
+    GAP_PENDING.store(true, Ordering::Relaxed);
+
+    critical_section::with(|cs| {
+        if let Some(recv) = RECEIVER.borrow(cs).borrow_mut().as_mut() {
+            if let Ok(Some(cmd)) = recv.event_instant(now) {
+                // if the queue is full the command is dropped; the main
+                // concern here is never blocking inside an isr
+                let _ = IR_CMDS.try_send(cmd);
+            }
+        }
+    });
+}
 
-    // move ir receiver to global for interrupt access
-    unsafe {
-        RECEIVER.replace(ir);
+// timer interrupt every 50 microseconds; drives the embassy time driver and
+// watches for a held/stuck line so a repeat sequence can be finalized
+#[avr_device::interrupt(atmega328p)]
+fn TIMER0_COMPA() {
+    clock::tick_isr();
+
+    if GAP_PENDING.load(Ordering::Relaxed) {
+        let now = clock::now();
+        if now.wrapping_sub(LAST_EDGE.load(Ordering::Relaxed)) > GAP_TICKS {
+            GAP_PENDING.store(false, Ordering::Relaxed);
+
+            // the line has been quiet for a full frame gap: whatever partial
+            // signal the receiver was decoding is stale, and the button that
+            // was being held (if any) has been released
+            critical_section::with(|cs| {
+                if let Some(recv) = RECEIVER.borrow(cs).borrow_mut().as_mut() {
+                    recv.reset();
+                }
+            });
+            let _ = IR_CMDS.try_send(DecodedCmd::Released);
+        }
     }
+}
 
-    // enable interrupts globally
-    unsafe { avr_device::interrupt::enable() };
+// timer1 compare match: advance the servo bank's pulse-multiplexing state
+// machine and reprogram OCR1A for the next edge
+#[avr_device::interrupt(atmega328p)]
+fn TIMER1_COMPA() {
+    let tc1 = unsafe { &*arduino_hal::pac::TC1::ptr() };
+    let now = tc1.tcnt1.read().bits();
+    let ticks_to_next =
+        critical_section::with(|cs| SERVO_BANK.borrow(cs).borrow_mut().advance());
+    tc1.ocr1a.write(|w| w.bits(now.wrapping_add(ticks_to_next)));
+}
 
-    // test servo on startup min -> max -> min
-    uwriteln!(&mut serial, "Testing servo... MIN -> MAX -> MIN.\r").unwrap_infallible();
-    SERVO.set_pos(&dp.TC1, SERVO_MIN);
-    uwriteln!(&mut serial, "moved to start ({} counts)\r", SERVO_MIN).unwrap_infallible();
-    delay_ms(1000);
-    SERVO.set_pos(&dp.TC1, SERVO_MAX);
-    uwriteln!(&mut serial, "moved to end ({} counts)\r", SERVO_MAX).unwrap_infallible();
-    delay_ms(1000);
-    SERVO.set_pos(&dp.TC1, SERVO_MIN);
-    uwriteln!(&mut serial, "back to start ({} counts)\r", SERVO_MIN).unwrap_infallible();
-    delay_ms(1000);
+// uart receive complete: hand the byte to serial_task
+#[avr_device::interrupt(atmega328p)]
+fn USART_RX() {
+    let usart0 = unsafe { &*arduino_hal::pac::USART0::ptr() };
+    let byte = usart0.udr0.read().bits();
+    let _ = SERIAL_BYTES.try_send(byte);
+}
 
-    uwriteln!(&mut serial, "Startup complete :]\r").unwrap_infallible();
+// true while the last dispatched action was a Press, i.e. a button is being
+// held down and is waiting on the gap watchdog to report its release
+static PRESS_HELD: AtomicBool = AtomicBool::new(false);
 
+#[embassy_executor::task]
+async fn ir_task() {
     loop {
-        // check for ir commands
-        if let Some(cmd) = avr_device::interrupt::free(|cs| CMD.borrow(cs).take()) {
+        let cmd = IR_CMDS.receive().await;
+
+        if matches!(cmd, DecodedCmd::Released) {
+            if PRESS_HELD.swap(false, Ordering::Relaxed) {
+                actions::dispatch(actions::Action::Release, &SERVO_BANK, PRIMARY_CHANNEL).await;
+            }
+            if let Some(serial) = SERIAL.lock().await.as_mut() {
+                uwriteln!(serial, "IR idle: button released\r").unwrap_infallible();
+            }
+            continue;
+        }
+
+        let (proto, addr, code, repeat) = match cmd {
+            DecodedCmd::Nec(c) => ("NEC", c.addr as u16, c.cmd, c.repeat),
+            DecodedCmd::Rc5(c) => ("RC5", c.addr as u16, c.cmd, c.repeat),
+            DecodedCmd::Samsung(c) => ("Samsung", c.addr, c.cmd, c.repeat),
+            DecodedCmd::Released => unreachable!(),
+        };
+
+        if let Some(serial) = SERIAL.lock().await.as_mut() {
             uwriteln!(
-                &mut serial,
-                "NEC Cmd: Address: {}, Command: {}, Repeat?: {}\r",
-                cmd.addr,
-                cmd.cmd,
-                cmd.repeat
+                serial,
+                "{} Cmd: Address: {}, Command: {}, Repeat?: {}\r",
+                proto,
+                addr,
+                code,
+                repeat
             )
             .unwrap_infallible();
+        }
 
-            // only respond to button presses, not repeats
-            if !cmd.repeat && cmd.cmd != 0 {
-                // toggle servo between min and max
-                let new_pos = SERVO.toggle(&dp.TC1);
+        // only respond to button presses, not repeats; whether code 0 is
+        // bound to anything is up to the action table, since it's a real
+        // button (e.g. digit "0") on RC5/Samsung remotes, not just NEC noise
+        if repeat {
+            continue;
+        }
 
-                // back to start after 1s
-                delay_ms(1000);
-                SERVO.set_pos(&dp.TC1, SERVO_MIN);
+        if let Some(action) = actions::lookup(addr, code) {
+            PRESS_HELD.store(action == actions::Action::Press, Ordering::Relaxed);
+            actions::dispatch(action, &SERVO_BANK, PRIMARY_CHANNEL).await;
 
+            let new_pos =
+                critical_section::with(|cs| SERVO_BANK.borrow(cs).borrow().get_pos(PRIMARY_CHANNEL));
+            if let Some(serial) = SERIAL.lock().await.as_mut() {
                 uwriteln!(
-                    &mut serial,
+                    serial,
                     "Servo position: {} counts ({}ms pulse)\r",
                     new_pos,
                     new_pos * 4 // each count is 4 microseconds
                 )
                 .unwrap_infallible();
             }
+        } else if let Some(serial) = SERIAL.lock().await.as_mut() {
+            uwriteln!(serial, "No action bound to addr {} cmd {}\r", addr, code)
+                .unwrap_infallible();
         }
+    }
+}
+
+#[embassy_executor::task]
+async fn serial_task() {
+    let mut line_buf = [0u8; 32];
+    let mut line_len = 0usize;
+
+    loop {
+        let byte = SERIAL_BYTES.receive().await;
+        match byte {
+            b'\n' | b'\r' => {
+                if line_len == 0 {
+                    continue;
+                }
+                let len = line_len;
+                line_len = 0;
+
+                let Ok(line) = core::str::from_utf8(&line_buf[..len]) else {
+                    continue;
+                };
+
+                if let Some(command) = serial_cmd::parse(line) {
+                    critical_section::with(|cs| {
+                        let mut bank = SERVO_BANK.borrow(cs).borrow_mut();
+                        match command {
+                            Command::Min => bank.set_pos(PRIMARY_CHANNEL, SERVO_MIN),
+                            Command::Max => bank.set_pos(PRIMARY_CHANNEL, SERVO_MAX),
+                            Command::Toggle => {
+                                bank.toggle(PRIMARY_CHANNEL);
+                            }
+                            Command::Pos(pos) => bank.set_pos(PRIMARY_CHANNEL, pos),
+                        }
+                    });
+
+                    let new_pos = critical_section::with(|cs| {
+                        SERVO_BANK.borrow(cs).borrow().get_pos(PRIMARY_CHANNEL)
+                    });
+                    if let Some(serial) = SERIAL.lock().await.as_mut() {
+                        uwriteln!(serial, "Servo position: {} counts\r", new_pos)
+                            .unwrap_infallible();
+                    }
+                } else if let Some(serial) = SERIAL.lock().await.as_mut() {
+                    uwriteln!(serial, "unrecognised command\r").unwrap_infallible();
+                }
+            }
+            _ if line_len < line_buf.len() => {
+                line_buf[line_len] = byte;
+                line_len += 1;
+            }
+            _ => {} // line too long; drop bytes until the next newline
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn startup_sweep_task() {
+    if let Some(serial) = SERIAL.lock().await.as_mut() {
+        uwriteln!(serial, "Testing servo... MIN -> MAX -> MIN.\r").unwrap_infallible();
+    }
+    actions::dispatch(actions::Action::Sweep, &SERVO_BANK, PRIMARY_CHANNEL).await;
+    if let Some(serial) = SERIAL.lock().await.as_mut() {
+        uwriteln!(serial, "Startup complete :]\r").unwrap_infallible();
+    }
+}
 
-        delay_ms(100);
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+#[arduino_hal::entry]
+fn main() -> ! {
+    // initialise device peripherals, pins and serial
+    let dp = arduino_hal::Peripherals::take().unwrap(); // dp = device peripherals
+    let pins = arduino_hal::pins!(dp);
+    let serial = arduino_hal::default_serial!(dp, pins, 57600);
+
+    // start the timer backing both ir timing and embassy's Instant::now()
+    clock::start(&dp.TC0);
+
+    // attach the primary servo (d9) to the bank; more channels can be
+    // attached the same way up to servo::MAX_CHANNELS
+    critical_section::with(|cs| {
+        SERVO_BANK
+            .borrow(cs)
+            .borrow_mut()
+            .attach(PRIMARY_CHANNEL, pins.d9.into_output().downgrade());
+    });
+
+    // configure Timer1 as a free-running counter driving the bank's
+    // software pulse multiplexing (see servo.rs); no hardware pwm output is
+    // used since the bank drives arbitrary gpio pins from the isr instead
+    dp.TC1.tccr1a.write(|w| w);
+    dp.TC1.tccr1b.write(|w| w.cs1().prescale_64()); // 16mhz / 64 = 4us/count
+    dp.TC1.ocr1a.write(|w| w.bits(SERVO_MIN));
+    dp.TC1.timsk1.write(|w| w.ocie1a().set_bit());
+
+    // enable the uart rx-complete interrupt (default_serial! only enables
+    // rxen/txen) so serial commands can arrive alongside ir commands
+    dp.USART0.ucsr0b.modify(|_, w| w.rxcie0().set_bit());
+
+    // configure pin change interrupts for the ir receiver
+    dp.EXINT.pcicr.write(|w| unsafe { w.bits(0b100) });
+    dp.EXINT.pcmsk2.write(|w| w.bits(0b100)); // PCINT18 = PD2
+
+    // multi-protocol ir receiver (auto-detects nec/rc5/samsung)
+    let ir = IrReceiver::new(clock::FREQ, pins.d2);
+    critical_section::with(|cs| {
+        RECEIVER.borrow(cs).borrow_mut().replace(ir);
+    });
+
+    // move the serial port into its shared lock before any task can touch it
+    if let Ok(mut guard) = SERIAL.try_lock() {
+        *guard = Some(serial);
     }
+
+    // enable interrupts globally
+    unsafe { avr_device::interrupt::enable() };
+
+    // hand off to the executor: everything from here on is task-driven
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(ir_task()).unwrap();
+        spawner.spawn(serial_task()).unwrap();
+        spawner.spawn(startup_sweep_task()).unwrap();
+    });
 }
@@ -0,0 +1,21 @@
+// a parsed serial command line
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Pos(u16),
+    Min,
+    Max,
+    Toggle,
+}
+
+/// parse a newline-terminated command line: `POS <n>`, `MIN`, `MAX`, `TOGGLE`
+pub fn parse(line: &str) -> Option<Command> {
+    match line.trim() {
+        "MIN" => Some(Command::Min),
+        "MAX" => Some(Command::Max),
+        "TOGGLE" => Some(Command::Toggle),
+        line => line
+            .strip_prefix("POS ")
+            .and_then(|rest| rest.trim().parse().ok())
+            .map(Command::Pos),
+    }
+}
@@ -1,43 +1,136 @@
-use avr_device::interrupt::Mutex;
-use core::cell::Cell;
+use arduino_hal::hal::port::Dynamic;
+use arduino_hal::port::mode::Output;
+use arduino_hal::port::Pin;
 
-// servo positions
+// servo positions, in timer1 counts (4us/count with the 64 prescaler set up in main.rs)
 pub const SERVO_MIN: u16 = 125; // 0.5ms pulse
 pub const SERVO_MAX: u16 = 625; // 2.5ms pulse
 pub const SERVO_MID: u16 = 375; // 1.5ms pulse
 
-pub struct Servo {
-    pos: Mutex<Cell<u16>>,
+// channels the bank can drive off one timer; kept small enough that
+// MAX_CHANNELS * SERVO_MAX always fits inside one 20ms frame
+pub const MAX_CHANNELS: usize = 6;
+
+// one 20ms refresh frame, in timer1 counts (16mhz / 64 prescaler = 4us/count)
+pub const FRAME_TICKS: u16 = 5000;
+
+// sentinel meaning "no channel currently selected"
+const NONE_SELECTED: usize = MAX_CHANNELS;
+
+struct Channel {
+    pin: Option<Pin<Output, Dynamic>>,
+    pulse: u16,
 }
 
-impl Servo {
-    /// new servo instance
+impl Channel {
+    const fn new() -> Self {
+        Self {
+            pin: None,
+            pulse: SERVO_MIN,
+        }
+    }
+}
+
+/// software pulse-multiplexed bank of servos sharing a single 16-bit timer
+///
+/// only one channel's pin is ever driven high at a time: [`ServoBank::advance`]
+/// drops the current channel low, raises the next attached channel high and
+/// returns how many ticks until that channel's pulse ends, for the caller to
+/// reprogram OCR1A with. once every attached channel has had its turn the
+/// bank idles for the remainder of the 20ms frame so each servo still sees a
+/// steady 50hz refresh.
+///
+/// holds no internal locking of its own: it's shared between the embassy
+/// tasks and the TIMER1_COMPA isr behind a `CsMutex<RefCell<ServoBank>>`, so
+/// every access goes through `critical_section::with` to serialize it.
+pub struct ServoBank {
+    channels: [Channel; MAX_CHANNELS],
+    current: usize, // channel currently driven high, or NONE_SELECTED
+    frame_pos: u16,  // ticks used so far in the current 20ms frame
+}
+
+impl ServoBank {
+    /// new bank with no channels attached
     pub const fn new() -> Self {
         Self {
-            pos: Mutex::new(Cell::new(SERVO_MIN)),
+            channels: [
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+            ],
+            current: NONE_SELECTED,
+            frame_pos: 0,
         }
     }
 
-    /// set servo position
-    pub fn set_pos(&self, timer: &arduino_hal::pac::TC1, pos: u16) {
-        avr_device::interrupt::free(|cs| self.pos.borrow(cs).set(pos));
-        timer.ocr1a.write(|w| w.bits(pos));
+    /// bind a gpio pin to a channel; the pin is held low until its turn
+    pub fn attach(&mut self, channel: usize, mut pin: Pin<Output, Dynamic>) {
+        pin.set_low();
+        self.channels[channel].pin = Some(pin);
+    }
+
+    /// set a channel's pulse width (clamped to SERVO_MIN..=SERVO_MAX)
+    pub fn set_pos(&mut self, channel: usize, pos: u16) {
+        self.channels[channel].pulse = pos.clamp(SERVO_MIN, SERVO_MAX);
     }
 
-    /// get current servo position
-    pub fn get_pos(&self) -> u16 {
-        avr_device::interrupt::free(|cs| self.pos.borrow(cs).get())
+    /// read back a channel's pulse width
+    pub fn get_pos(&self, channel: usize) -> u16 {
+        self.channels[channel].pulse
     }
 
-    /// toggle servo between min and max
-    pub fn toggle(&self, timer: &arduino_hal::pac::TC1) -> u16 {
-        let curr_pos = self.get_pos();
-        let new_pos = if curr_pos <= SERVO_MID {
+    /// toggle a channel between min and max
+    pub fn toggle(&mut self, channel: usize) -> u16 {
+        let new_pos = if self.get_pos(channel) <= SERVO_MID {
             SERVO_MAX
         } else {
             SERVO_MIN
         };
-        self.set_pos(timer, new_pos);
+        self.set_pos(channel, new_pos);
         new_pos
     }
+
+    /// advance the multiplexing state machine one step
+    ///
+    /// call this from the TIMER1_COMPA isr; the return value is how many
+    /// ticks from now OCR1A should be set to fire next.
+    pub fn advance(&mut self) -> u16 {
+        // drop whatever channel just finished its pulse
+        if self.current < MAX_CHANNELS {
+            if let Some(pin) = self.channels[self.current].pin.as_mut() {
+                pin.set_low();
+            }
+        }
+
+        // find the next attached channel after current, wrapping back to 0
+        // once we've idled (current == NONE_SELECTED) or fallen off the end
+        let mut next = if self.current >= MAX_CHANNELS {
+            0
+        } else {
+            self.current + 1
+        };
+        while next < MAX_CHANNELS && self.channels[next].pin.is_none() {
+            next += 1;
+        }
+
+        if next < MAX_CHANNELS {
+            // raise the next channel and schedule its pulse end
+            let pulse = self.channels[next].pulse;
+            if let Some(pin) = self.channels[next].pin.as_mut() {
+                pin.set_high();
+            }
+            self.current = next;
+            self.frame_pos += pulse;
+            pulse
+        } else {
+            // ran out of attached channels: idle for the rest of the frame
+            let remaining = FRAME_TICKS.saturating_sub(self.frame_pos);
+            self.current = NONE_SELECTED;
+            self.frame_pos = 0;
+            remaining.max(1)
+        }
+    }
 }